@@ -0,0 +1,176 @@
+//! Async driver variant, available behind the `async` Cargo feature, for
+//! Embassy-style executors. Mirrors `crate::PMS5003` but awaits serial bytes
+//! instead of spinning on `block!` (see `wake()` for how its warm-up differs;
+//! there is also no counterpart of `read_nonblocking()`, and `measure()`
+//! starts a fresh frame on every call). Depends on `embedded-hal-async` for
+//! `delay::DelayNs` and on `embedded-io-async` for `Read`/`Write`, since
+//! `embedded-hal-async` itself has no serial/UART traits; the digital pin
+//! stays on `embedded_hal::digital::v2::OutputPin` so it matches the
+//! blocking driver rather than pulling in a second, incompatible
+//! `OutputPin`. Frame parsing and command/ACK framing are shared with the
+//! blocking driver via `advance_state()`, `build_command_frame()` and
+//! `verify_response()`, so there is a single source of truth for the
+//! protocol.
+
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal_async::delay::DelayNs;
+use embedded_io_async::{Read, Write};
+
+use crate::{
+    advance_preamble_scan, advance_state, build_command_frame, verify_response, Error,
+    Measurement, RxState,
+};
+use crate::{CMD_MODE, CMD_READ, CMD_SLEEP, PMS5003_DATA_START, PMS5003_RESPONSE_LENGTH};
+
+/// Warm-up delay after `wake()`. `DelayNs::delay_ms()` takes a `u32`, so
+/// unlike the blocking driver's `DelayMs<u8>` this needs no chunking.
+const PMS5003_WAKEUP_DELAY_MS: u32 = 30_000;
+
+pub struct PMS5003Async<TTY, P, D> {
+    /// Serial port
+    tty: TTY,
+
+    /// Data/command pin
+    dc: P,
+
+    /// Reset pin
+    rst: P,
+
+    /// Timer
+    delay: D,
+}
+
+impl<TTY, P, D, E, PE> PMS5003Async<TTY, P, D>
+where
+    TTY: Read<Error = E> + Write<Error = E>,
+    P: OutputPin<Error = PE>,
+    D: DelayNs,
+{
+    pub fn new(tty: TTY, dc: P, rst: P, delay: D) -> Self {
+        PMS5003Async { tty, dc, rst, delay }
+    }
+
+    pub async fn init(&mut self) -> Result<(), PE> {
+        self.dc.set_high()?;
+        self.rst.set_high()?;
+        self.reset().await
+    }
+
+    pub async fn reset(&mut self) -> Result<(), PE> {
+        self.delay.delay_ms(100).await;
+        self.rst.set_low()?;
+        self.delay.delay_ms(100).await;
+        self.rst.set_high()?;
+        Ok(())
+    }
+
+    pub async fn measure(&mut self) -> Result<Measurement, Error<E>> {
+        let mut state = RxState::WaitMagic1;
+        let mut checksum = 0u16;
+        loop {
+            let byte = self.read_byte().await?;
+            let (next, result) = advance_state(state, &mut checksum, byte);
+            state = next;
+            if let Some(result) = result {
+                return result.map_err(Error::from);
+            }
+        }
+    }
+
+    /// Calls `measure()` until it reports a ready reading or `attempts` is
+    /// exhausted; see `crate::PMS5003::measure_stable()`.
+    pub async fn measure_stable(&mut self, attempts: u8) -> Result<Measurement, Error<E>> {
+        for _ in 0..attempts {
+            let measurement = self.measure().await?;
+            if measurement.is_ready() {
+                return Ok(measurement);
+            }
+        }
+        Err(Error::NotReady)
+    }
+
+    /// Reads a single byte, looping over `embedded_io_async::Read::read()`
+    /// since it may return having filled less of the buffer than asked for.
+    async fn read_byte(&mut self) -> Result<u8, Error<E>> {
+        let mut buf = [0u8; 1];
+        loop {
+            let n = self.tty.read(&mut buf).await.map_err(Error::Serial)?;
+            if n > 0 {
+                return Ok(buf[0]);
+            }
+        }
+    }
+
+    async fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N], Error<E>> {
+        let mut data = [0u8; N];
+        for byte in data.iter_mut() {
+            *byte = self.read_byte().await?;
+        }
+        Ok(data)
+    }
+
+    /// Writes the whole buffer, looping over `embedded_io_async::Write::write()`
+    /// since it may perform a short write.
+    async fn write_all(&mut self, bytes: &[u8]) -> Result<(), Error<E>> {
+        let mut written = 0;
+        while written < bytes.len() {
+            written += self.tty.write(&bytes[written..]).await.map_err(Error::Serial)?;
+        }
+        self.tty.flush().await.map_err(Error::Serial)?;
+        Ok(())
+    }
+
+    async fn send_command(&mut self, cmd: u8, data: u16) -> Result<(), Error<E>> {
+        let frame = build_command_frame(cmd, data);
+        self.write_all(&frame).await
+    }
+
+    async fn expect_response(&mut self) -> Result<(), Error<E>> {
+        let mut matched = 0;
+        loop {
+            let byte = self.read_byte().await?;
+            matched = advance_preamble_scan(matched, byte);
+            if matched >= PMS5003_DATA_START.len() {
+                break;
+            }
+        }
+
+        let raw_length: [u8; 2] = self.read_bytes().await?;
+        let body: [u8; PMS5003_RESPONSE_LENGTH] = self.read_bytes().await?;
+        let raw_checksum: [u8; 2] = self.read_bytes().await?;
+        verify_response(raw_length, body, raw_checksum).map_err(Error::from)
+    }
+
+    pub async fn set_passive_mode(&mut self) -> Result<(), Error<E>> {
+        self.send_command(CMD_MODE, 0x00).await?;
+        self.expect_response().await
+    }
+
+    pub async fn set_active_mode(&mut self) -> Result<(), Error<E>> {
+        self.send_command(CMD_MODE, 0x01).await?;
+        self.expect_response().await
+    }
+
+    pub async fn request_read(&mut self) -> Result<(), Error<E>> {
+        self.send_command(CMD_READ, 0x00).await
+    }
+
+    pub async fn sleep(&mut self) -> Result<(), Error<E>> {
+        self.send_command(CMD_SLEEP, 0x00).await
+    }
+
+    /// Unlike `crate::PMS5003::wake()`, this does not drain the serial FIFO
+    /// after the warm-up delay: `embedded_io_async::Read::read()` has no
+    /// non-blocking "is anything buffered right now" mode to loop over
+    /// without blocking on the next real frame, so there is nothing to
+    /// drain *into* without an executor-specific timeout this crate doesn't
+    /// depend on. This is safe because `measure()` builds a fresh `RxState`
+    /// and checksum on every call and `expect_response()`'s preamble scan
+    /// resynchronizes on stray bytes, so any leftover bytes from the
+    /// warm-up period are simply skipped rather than misparsed.
+    pub async fn wake(&mut self) -> Result<(), Error<E>> {
+        self.send_command(CMD_SLEEP, 0x01).await?;
+        self.delay.delay_ms(PMS5003_WAKEUP_DELAY_MS).await;
+        Ok(())
+    }
+}
@@ -3,12 +3,28 @@
 #[macro_use]
 extern crate nb;
 
+use core::mem;
+
 use embedded_hal::blocking::delay::DelayMs;
-use embedded_hal::serial::Read;
+use embedded_hal::serial::{Read, Write};
 use embedded_hal::digital::v2::OutputPin;
 
-const PMS5003_DATA_START: [u8;2] = [0x42, 0x4d];
-const PMS5003_DATA_LENGTH: usize = 26;
+pub(crate) const PMS5003_DATA_START: [u8;2] = [0x42, 0x4d];
+pub(crate) const PMS5003_DATA_LENGTH: usize = 26;
+pub(crate) const PMS5003_RESPONSE_LENGTH: usize = 2;
+
+/// Command byte for switching between active and passive mode.
+pub(crate) const CMD_MODE: u8 = 0xe1;
+/// Command byte requesting a single frame while in passive mode.
+pub(crate) const CMD_READ: u8 = 0xe2;
+/// Command byte for entering/leaving low-power sleep (fan and laser off).
+pub(crate) const CMD_SLEEP: u8 = 0xe4;
+
+/// Warm-up delay after `wake()`, in one `PMS5003_WAKEUP_STEP_MS` step.
+const PMS5003_WAKEUP_STEP_MS: u8 = 250;
+/// Number of `PMS5003_WAKEUP_STEP_MS` steps to cover the ~30s datasheet
+/// stabilization period (`DelayMs<u8>` caps a single call at 255ms).
+const PMS5003_WAKEUP_STEPS: u16 = 120;
 
 macro_rules! extract_u16 {
     ($data:expr) => {
@@ -23,7 +39,7 @@ macro_rules! checksum {
     ($data:ident) => {{
         let mut checksum = 0u16;
         for i in 0..$data.len() {
-            checksum += $data[i] as u16;
+            checksum = checksum.wrapping_add($data[i] as u16);
         }
         checksum
     }};
@@ -39,6 +55,9 @@ macro_rules! read {
     }};
 }
 
+#[cfg(feature = "async")]
+pub mod asynch;
+
 /// PMS5003 errors
 #[derive(Debug)]
 pub enum Error<E> {
@@ -48,6 +67,27 @@ pub enum Error<E> {
     InvalidLength(u16),
     /// Serial read error
     Serial(E),
+    /// `measure_stable()` exhausted its attempt budget before the sensor
+    /// reported a non-zero particle count
+    NotReady,
+}
+
+/// Protocol-level frame errors, independent of the serial transport's error
+/// type. Produced by `advance_state()` and converted into the transport's
+/// own `Error<E>` by each driver.
+#[derive(Debug)]
+pub(crate) enum FrameError {
+    InvalidLength(u16),
+    InvalidData(u16, u16),
+}
+
+impl<E> From<FrameError> for Error<E> {
+    fn from(e: FrameError) -> Self {
+        match e {
+            FrameError::InvalidLength(length) => Error::InvalidLength(length),
+            FrameError::InvalidData(received, expected) => Error::InvalidData(received, expected),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -69,11 +109,154 @@ pub struct Absolutes {
 
 #[derive(Debug)]
 pub struct Measurement {
+    /// CF=1 (factory-calibration, "standard particle") concentrations.
     pub ug_per_m3: Concentrations,
+    /// Atmospheric-environment concentrations — use these, not the CF=1
+    /// set above, when feeding AQI calculations.
     pub ug_per_m3_atmospheric: Concentrations,
     pub per_0p1l: Absolutes,
 }
 
+/// State of the frame receiver, driven one byte at a time by
+/// `advance_state()`. Shared by the blocking and async drivers.
+pub(crate) enum RxState {
+    WaitMagic1,
+    WaitMagic2,
+    ReadLength { byte_idx: usize, bytes: [u8; 2] },
+    ReadBody { idx: usize, bytes: [u8; PMS5003_DATA_LENGTH] },
+    ReadChecksum { byte_idx: usize, bytes: [u8; 2], body: [u8; PMS5003_DATA_LENGTH] },
+}
+
+/// Advances a `0x42 0x4d` preamble scan by one byte, given how many of the
+/// preamble's bytes have matched so far. Correctly resyncs when a byte that
+/// doesn't extend the match is itself `PMS5003_DATA_START[0]`, the same way
+/// `advance_state`'s `WaitMagic1`/`WaitMagic2` arms do. Shared by
+/// `expect_response()` in both the blocking and async drivers.
+pub(crate) fn advance_preamble_scan(matched: usize, byte: u8) -> usize {
+    if matched < PMS5003_DATA_START.len() && byte == PMS5003_DATA_START[matched] {
+        matched + 1
+    } else if byte == PMS5003_DATA_START[0] {
+        1
+    } else {
+        0
+    }
+}
+
+/// Feeds one received byte through the frame receiver state machine,
+/// returning the next state and, once a full frame has been consumed, the
+/// parsed measurement (or the protocol error that reset the receiver). This
+/// is the single source of truth for the wire protocol, shared by the
+/// blocking `PMS5003` and the async `asynch::PMS5003Async`.
+pub(crate) fn advance_state(
+    state: RxState,
+    checksum: &mut u16,
+    byte: u8,
+) -> (RxState, Option<Result<Measurement, FrameError>>) {
+    match state {
+        RxState::WaitMagic1 => {
+            if byte == PMS5003_DATA_START[0] {
+                *checksum = byte as u16;
+                (RxState::WaitMagic2, None)
+            } else {
+                (RxState::WaitMagic1, None)
+            }
+        }
+        RxState::WaitMagic2 => {
+            if byte == PMS5003_DATA_START[1] {
+                *checksum = checksum.wrapping_add(byte as u16);
+                (RxState::ReadLength { byte_idx: 0, bytes: [0; 2] }, None)
+            } else if byte == PMS5003_DATA_START[0] {
+                // Stray byte is itself a magic byte: treat it as the start
+                // of a new frame instead of falling back to WaitMagic1.
+                *checksum = byte as u16;
+                (RxState::WaitMagic2, None)
+            } else {
+                *checksum = 0;
+                (RxState::WaitMagic1, None)
+            }
+        }
+        RxState::ReadLength { mut byte_idx, mut bytes } => {
+            bytes[byte_idx] = byte;
+            *checksum = checksum.wrapping_add(byte as u16);
+            byte_idx += 1;
+            if byte_idx == bytes.len() {
+                let length = extract_u16!(bytes);
+                if length != (PMS5003_DATA_LENGTH + 2) as u16 {
+                    *checksum = 0;
+                    return (RxState::WaitMagic1, Some(Err(FrameError::InvalidLength(length))));
+                }
+                (RxState::ReadBody { idx: 0, bytes: [0; PMS5003_DATA_LENGTH] }, None)
+            } else {
+                (RxState::ReadLength { byte_idx, bytes }, None)
+            }
+        }
+        RxState::ReadBody { mut idx, mut bytes } => {
+            bytes[idx] = byte;
+            *checksum = checksum.wrapping_add(byte as u16);
+            idx += 1;
+            if idx == bytes.len() {
+                (RxState::ReadChecksum { byte_idx: 0, bytes: [0; 2], body: bytes }, None)
+            } else {
+                (RxState::ReadBody { idx, bytes }, None)
+            }
+        }
+        RxState::ReadChecksum { mut byte_idx, mut bytes, body } => {
+            bytes[byte_idx] = byte;
+            byte_idx += 1;
+            if byte_idx == bytes.len() {
+                let expected = extract_u16!(bytes);
+                let received = *checksum;
+                *checksum = 0;
+                if received != expected {
+                    return (RxState::WaitMagic1, Some(Err(FrameError::InvalidData(received, expected))));
+                }
+                (RxState::WaitMagic1, Some(Ok(Measurement::parse(body))))
+            } else {
+                (RxState::ReadChecksum { byte_idx, bytes, body }, None)
+            }
+        }
+    }
+}
+
+/// Builds a 7-byte command frame (`0x42 0x4d CMD DATA_H DATA_L LRC_H LRC_L`),
+/// where the LRC is the checksum of the preceding five bytes. Shared by the
+/// blocking and async drivers so there is a single source of truth for the
+/// command encoding.
+pub(crate) fn build_command_frame(cmd: u8, data: u16) -> [u8; 7] {
+    let mut frame = [0u8; 7];
+    frame[0] = PMS5003_DATA_START[0];
+    frame[1] = PMS5003_DATA_START[1];
+    frame[2] = cmd;
+    frame[3] = (data >> 8) as u8;
+    frame[4] = data as u8;
+    let header = &frame[..5];
+    let lrc = checksum!(header);
+    frame[5] = (lrc >> 8) as u8;
+    frame[6] = lrc as u8;
+    frame
+}
+
+/// Validates the length and checksum fields of the 8-byte ACK frame the
+/// sensor sends in response to a mode-change command, given the bytes read
+/// after the `0x42 0x4d` preamble. Shared by the blocking and async drivers
+/// so there is a single source of truth for the ACK framing.
+pub(crate) fn verify_response(
+    raw_length: [u8; 2],
+    body: [u8; PMS5003_RESPONSE_LENGTH],
+    raw_checksum: [u8; 2],
+) -> Result<(), FrameError> {
+    let length = extract_u16!(raw_length);
+    if length != (PMS5003_RESPONSE_LENGTH + 2) as u16 {
+        return Err(FrameError::InvalidLength(length));
+    }
+    let expected = extract_u16!(raw_checksum);
+    let received = checksum!(PMS5003_DATA_START) + checksum!(raw_length) + checksum!(body);
+    if received != expected {
+        return Err(FrameError::InvalidData(received, expected));
+    }
+    Ok(())
+}
+
 impl Measurement {
     pub fn parse(data: [u8;PMS5003_DATA_LENGTH]) -> Self {
         Measurement {
@@ -97,6 +280,42 @@ impl Measurement {
             },
         }
     }
+
+    /// Whether this reading looks like real data rather than the all-zero
+    /// garbage the sensor reports while freshly powered or just woken.
+    pub fn is_ready(&self) -> bool {
+        self.per_0p1l.pm0p3 != 0 || self.per_0p1l.pm0p5 != 0 || self.per_0p1l.pm1p0 != 0
+    }
+
+    /// CF=1 PM1.0 concentration, in µg/m³.
+    pub fn pm1p0(&self) -> u16 {
+        self.ug_per_m3.pm1p0
+    }
+
+    /// CF=1 PM2.5 concentration, in µg/m³.
+    pub fn pm2p5(&self) -> u16 {
+        self.ug_per_m3.pm2p5
+    }
+
+    /// CF=1 PM10.0 concentration, in µg/m³.
+    pub fn pm10p0(&self) -> u16 {
+        self.ug_per_m3.pm10p0
+    }
+
+    /// Atmospheric-environment PM1.0 concentration, in µg/m³.
+    pub fn pm1p0_atmospheric(&self) -> u16 {
+        self.ug_per_m3_atmospheric.pm1p0
+    }
+
+    /// Atmospheric-environment PM2.5 concentration, in µg/m³.
+    pub fn pm2p5_atmospheric(&self) -> u16 {
+        self.ug_per_m3_atmospheric.pm2p5
+    }
+
+    /// Atmospheric-environment PM10.0 concentration, in µg/m³.
+    pub fn pm10p0_atmospheric(&self) -> u16 {
+        self.ug_per_m3_atmospheric.pm10p0
+    }
 }
 
 pub struct PMS5003<TTY, P, D> {
@@ -111,16 +330,23 @@ pub struct PMS5003<TTY, P, D> {
 
     /// Timer
     delay: D,
+
+    /// State of the in-progress frame read, carried across calls to
+    /// `read_nonblocking()`.
+    state: RxState,
+
+    /// Running checksum of the bytes consumed for the frame in progress.
+    checksum: u16,
 }
 
 impl<TTY, P, D, E, PE> PMS5003<TTY, P, D>
 where
-    TTY: Read<u8, Error = E>,
+    TTY: Read<u8, Error = E> + Write<u8, Error = E>,
     P: OutputPin<Error = PE>,
     D: DelayMs<u8>,
 {
     pub fn new(tty: TTY, dc: P, rst: P, delay: D) -> Self {
-        PMS5003 { tty, dc, rst, delay }
+        PMS5003 { tty, dc, rst, delay, state: RxState::WaitMagic1, checksum: 0 }
     }
 
     pub fn init(&mut self) -> Result<(), PE> {
@@ -138,36 +364,273 @@ where
     }
 
     pub fn measure(&mut self) -> Result<Measurement, Error<E>> {
-        let mut expect = 0;
+        block!(self.read_nonblocking())
+    }
+
+    /// Calls `measure()` until it reports a ready reading or `attempts` is
+    /// exhausted, so callers don't have to hardcode timing assumptions to
+    /// discard the garbage first readings after power-on or `wake()`.
+    pub fn measure_stable(&mut self, attempts: u8) -> Result<Measurement, Error<E>> {
+        for _ in 0..attempts {
+            let measurement = self.measure()?;
+            if measurement.is_ready() {
+                return Ok(measurement);
+            }
+        }
+        Err(Error::NotReady)
+    }
+
+    /// Non-blocking equivalent of `measure()`: consumes whatever bytes are
+    /// currently available from the serial port and returns
+    /// `nb::Error::WouldBlock` once the FIFO runs dry, preserving progress
+    /// across calls so callers on cooperative/async runtimes don't have to
+    /// busy-wait for a whole frame.
+    pub fn read_nonblocking(&mut self) -> nb::Result<Measurement, Error<E>> {
+        loop {
+            let byte = self.tty.read().map_err(|e| e.map(Error::Serial))?;
+            // `WaitMagic1` is the default if this loop returns early below:
+            // it's also the reset state `advance_state()` falls back to on
+            // a bad magic byte, length mismatch or checksum failure.
+            let state = mem::replace(&mut self.state, RxState::WaitMagic1);
+            let (next, result) = advance_state(state, &mut self.checksum, byte);
+            self.state = next;
+            if let Some(result) = result {
+                return result.map_err(|e| nb::Error::Other(e.into()));
+            }
+        }
+    }
+
+    /// Sends the 7-byte command frame built by `build_command_frame()`.
+    fn send_command(&mut self, cmd: u8, data: u16) -> Result<(), Error<E>> {
+        let frame = build_command_frame(cmd, data);
+        for byte in frame.iter() {
+            block!(self.tty.write(*byte)).map_err(Error::Serial)?;
+        }
+        block!(self.tty.flush()).map_err(Error::Serial)?;
+        Ok(())
+    }
+
+    /// Reads the 8-byte ACK frame the sensor sends in response to a
+    /// mode-change command and validates it with `verify_response()`.
+    fn expect_response(&mut self) -> Result<(), Error<E>> {
+        let mut matched = 0;
         loop {
             let byte = block!(self.tty.read()).map_err(Error::Serial)?;
-            expect = if byte == PMS5003_DATA_START[expect] { expect + 1 } else { 0 };
-            if expect >= PMS5003_DATA_START.len() {
+            matched = advance_preamble_scan(matched, byte);
+            if matched >= PMS5003_DATA_START.len() {
                 break;
             }
         }
 
         let raw_length = read!(self, 2);
-        let length = extract_u16!(raw_length);
-        if length != (PMS5003_DATA_LENGTH + 2) as u16 {
-            return Err(Error::InvalidLength(length));
+        let body = read!(self, PMS5003_RESPONSE_LENGTH);
+        let raw_checksum = read!(self, 2);
+        verify_response(raw_length, body, raw_checksum).map_err(Error::from)
+    }
+
+    /// Switches the sensor into passive mode, where `measure()` only returns
+    /// data following a `request_read()`. Useful for duty-cycled deployments.
+    pub fn set_passive_mode(&mut self) -> Result<(), Error<E>> {
+        self.send_command(CMD_MODE, 0x00)?;
+        self.expect_response()
+    }
+
+    /// Switches the sensor back into its default active streaming mode.
+    pub fn set_active_mode(&mut self) -> Result<(), Error<E>> {
+        self.send_command(CMD_MODE, 0x01)?;
+        self.expect_response()
+    }
+
+    /// Requests a single frame while in passive mode. The sensor replies with
+    /// a regular data frame rather than an ACK, so follow up with `measure()`.
+    pub fn request_read(&mut self) -> Result<(), Error<E>> {
+        self.send_command(CMD_READ, 0x00)
+    }
+
+    /// Drops the fan and laser to cut power between samples.
+    pub fn sleep(&mut self) -> Result<(), Error<E>> {
+        self.send_command(CMD_SLEEP, 0x00)
+    }
+
+    /// Wakes the sensor, blocks for the datasheet warm-up period and drains
+    /// any partial frame left in the serial buffer so the next `measure()`
+    /// resynchronizes cleanly on the `0x42 0x4d` preamble.
+    pub fn wake(&mut self) -> Result<(), Error<E>> {
+        self.send_command(CMD_SLEEP, 0x01)?;
+        for _ in 0..PMS5003_WAKEUP_STEPS {
+            self.delay.delay_ms(PMS5003_WAKEUP_STEP_MS);
         }
-        let data = read!(self, PMS5003_DATA_LENGTH);
-        let raw_expected = read!(self, 2);
-        let expected = extract_u16!(raw_expected);
-        let received = checksum!(PMS5003_DATA_START) + checksum!(raw_length) + checksum!(data);
-        if received != expected {
-            return Err(Error::InvalidData(received, expected));
+        self.drain()
+    }
+
+    /// Discards any bytes currently sitting in the serial FIFO without
+    /// blocking for more, and resets the frame receiver so the next
+    /// `measure()` resynchronizes cleanly on the `0x42 0x4d` preamble.
+    fn drain(&mut self) -> Result<(), Error<E>> {
+        self.state = RxState::WaitMagic1;
+        self.checksum = 0;
+        loop {
+            match self.tty.read() {
+                Ok(_) => continue,
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(e)) => return Err(Error::Serial(e)),
+            }
         }
-        Ok(Measurement::parse(data))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
+    /// Feeds `bytes` through `advance_state()` one at a time, returning the
+    /// final state and the result of the last byte that produced one.
+    fn feed(bytes: &[u8]) -> (RxState, Option<Result<Measurement, FrameError>>) {
+        let mut state = RxState::WaitMagic1;
+        let mut checksum = 0u16;
+        let mut last_result = None;
+        for &byte in bytes {
+            let (next, result) = advance_state(state, &mut checksum, byte);
+            state = next;
+            if result.is_some() {
+                last_result = result;
+            }
+        }
+        (state, last_result)
+    }
+
+    fn valid_frame(body: [u8; PMS5003_DATA_LENGTH]) -> [u8; PMS5003_DATA_LENGTH + 6] {
+        let mut frame = [0u8; PMS5003_DATA_LENGTH + 6];
+        frame[0] = PMS5003_DATA_START[0];
+        frame[1] = PMS5003_DATA_START[1];
+        let raw_length = [0u8, (PMS5003_DATA_LENGTH + 2) as u8];
+        frame[2] = raw_length[0];
+        frame[3] = raw_length[1];
+        frame[4..4 + PMS5003_DATA_LENGTH].copy_from_slice(&body);
+        let received = checksum!(PMS5003_DATA_START) + checksum!(raw_length) + checksum!(body);
+        frame[4 + PMS5003_DATA_LENGTH] = (received >> 8) as u8;
+        frame[4 + PMS5003_DATA_LENGTH + 1] = received as u8;
+        frame
+    }
+
+    #[test]
+    fn advance_state_parses_a_valid_frame() {
+        let mut body = [0u8; PMS5003_DATA_LENGTH];
+        body[0] = 0x00;
+        body[1] = 0x0a; // ug_per_m3.pm1p0 = 10
+        let frame = valid_frame(body);
+
+        let (state, result) = feed(&frame);
+        match result {
+            Some(Ok(measurement)) => assert_eq!(measurement.ug_per_m3.pm1p0, 10),
+            other => panic!("expected a parsed measurement, got {:?}", other.map(|r| r.is_ok())),
+        }
+        // The receiver resets itself so it's ready for the next frame.
+        assert!(matches!(state, RxState::WaitMagic1));
+    }
+
+    #[test]
+    fn advance_state_resyncs_on_bad_magic_byte() {
+        let mut body = [0u8; PMS5003_DATA_LENGTH];
+        body[3] = 0x05;
+        let frame = valid_frame(body);
+
+        // Garbage, then a stray 0x42 that is not actually a frame, then a
+        // real frame: the receiver should drop the garbage and still parse
+        // the real frame that follows.
+        let mut bytes = [0u8; 3 + PMS5003_DATA_LENGTH + 6];
+        bytes[0] = 0xff;
+        bytes[1] = 0x00;
+        bytes[2] = PMS5003_DATA_START[0];
+        bytes[3..].copy_from_slice(&frame);
+
+        let (_, result) = feed(&bytes);
+        assert!(matches!(result, Some(Ok(_))));
+    }
+
+    #[test]
+    fn advance_state_reports_length_mismatch_and_resets() {
+        let bytes = [PMS5003_DATA_START[0], PMS5003_DATA_START[1], 0x00, 0x01];
+        let (state, result) = feed(&bytes);
+        assert!(matches!(result, Some(Err(FrameError::InvalidLength(1)))));
+        assert!(matches!(state, RxState::WaitMagic1));
+    }
+
+    #[test]
+    fn advance_state_reports_checksum_failure_and_resets() {
+        let mut body = [0u8; PMS5003_DATA_LENGTH];
+        body[0] = 0x01;
+        let mut frame = valid_frame(body);
+        let last = frame.len() - 1;
+        frame[last] ^= 0xff; // corrupt the checksum's low byte
+
+        let (state, result) = feed(&frame);
+        assert!(matches!(result, Some(Err(FrameError::InvalidData(_, _)))));
+        assert!(matches!(state, RxState::WaitMagic1));
+    }
+
+    #[test]
+    fn checksum_macro_wraps_instead_of_panicking() {
+        // 1000 bytes of 0xff sums to 255000, which overflows u16 (max
+        // 65535); with plain `+=` this would panic in a debug build.
+        let data = [0xffu8; 1000];
+        let received = checksum!(data);
+        assert_eq!(received, (255_000u32 % 65_536) as u16);
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn build_command_frame_computes_the_lrc_over_the_header() {
+        let frame = build_command_frame(0xe1, 0x0001);
+        assert_eq!(&frame[0..5], &[0x42, 0x4d, 0xe1, 0x00, 0x01]);
+        let header = [0x42u8, 0x4d, 0xe1, 0x00, 0x01];
+        let expected_lrc = checksum!(header);
+        assert_eq!(extract_u16!(frame, 5), expected_lrc);
     }
 
+    #[test]
+    fn verify_response_accepts_a_correctly_checksummed_ack() {
+        let raw_length = [0u8, (PMS5003_RESPONSE_LENGTH + 2) as u8];
+        let body = [0xe1u8, 0x00];
+        let received = checksum!(PMS5003_DATA_START) + checksum!(raw_length) + checksum!(body);
+        let raw_checksum = [(received >> 8) as u8, received as u8];
+
+        assert!(verify_response(raw_length, body, raw_checksum).is_ok());
+    }
+
+    #[test]
+    fn verify_response_rejects_a_length_mismatch() {
+        let raw_length = [0u8, 0x01];
+        let body = [0xe1u8, 0x00];
+        let raw_checksum = [0u8, 0u8];
+
+        assert!(matches!(
+            verify_response(raw_length, body, raw_checksum),
+            Err(FrameError::InvalidLength(1))
+        ));
+    }
+
+    #[test]
+    fn verify_response_rejects_a_bad_checksum() {
+        let raw_length = [0u8, (PMS5003_RESPONSE_LENGTH + 2) as u8];
+        let body = [0xe1u8, 0x00];
+        let raw_checksum = [0xffu8, 0xff];
+
+        assert!(matches!(
+            verify_response(raw_length, body, raw_checksum),
+            Err(FrameError::InvalidData(_, _))
+        ));
+    }
+
+    #[test]
+    fn advance_preamble_scan_resyncs_on_a_stray_magic_byte() {
+        // `0x42 0x42 0x4d`: the second 0x42 fails to extend the match against
+        // 0x4d, but it's itself PMS5003_DATA_START[0], so the scan should
+        // treat it as the start of a new preamble rather than resetting to 0
+        // and missing the real 0x4d that follows.
+        let mut matched = 0;
+        for byte in [PMS5003_DATA_START[0], PMS5003_DATA_START[0], PMS5003_DATA_START[1]] {
+            matched = advance_preamble_scan(matched, byte);
+        }
+        assert_eq!(matched, PMS5003_DATA_START.len());
+    }
 }